@@ -1,4 +1,5 @@
-use std::io::{self, BufRead};
+use std::fs::File;
+use std::io::{self, BufRead, Write};
 use std::str::FromStr;
 
 #[derive(Copy, Clone, Debug)]
@@ -16,36 +17,158 @@ enum CompiledInstruction {
     NOP,
 
     // Used to push data onto the data stack
-    IMM(usize),
+    IMM(i64),
     PRINT,
+
+    // Addressable memory access
+    FETCH,
+    STORE,
+    HERE,
+    ALLOT,
+
+    // Structured control flow; targets are absolute indices into
+    // `instruction_memory`, filled in by backpatching at compile time.
+    JZ(usize),
+    BR(usize),
+    DO,
+    LOOP(usize),
+    // Pushes the current `DO...LOOP` index without consuming it.
+    I,
+
+    // Comparisons, Forth truth convention: -1 for true, 0 for false
+    EQ,
+    LT,
+    GT,
+    LE,
+    GE,
+
+    DIVMOD,
+    MOD,
+    NEGATE,
+    ABS,
+
+    // Text output
+    EMIT,
+    TYPE,
+    // Prints the literal string at this index in `VM::strings` verbatim.
+    PRINTSTR(usize),
+}
+
+// Net change in data-stack depth from executing `ins`, used by the
+// compile-time stack checker. `None` means the effect depends on context
+// the checker doesn't track (an indirect `CALL`/`JUMP`, or structured
+// control flow, which never reaches this function since it's handled
+// before `CompileMode::DefinitionBody` dispatch).
+fn instruction_effect(ins: &CompiledInstruction) -> Option<i64> {
+    use CompiledInstruction::*;
+    match ins {
+        IMM(_) | DUP | HERE | I => Some(1),
+        DROP | ALLOT | EMIT => Some(-1),
+        SWAP | NEGATE | ABS | FETCH | DIVMOD | RET | NOP | PRINTSTR(_) => Some(0),
+        ADD | SUB | MUL | DIV | MOD | PRINT | EQ | LT | GT | LE | GE => Some(-1),
+        STORE | TYPE => Some(-2),
+        CALL | JUMP | JZ(_) | BR(_) | DO | LOOP(_) => None,
+    }
+}
+
+// Compile-time state for the stack checker, live only while compiling the
+// body of a `:` definition that had a declared stack effect.
+struct EffectCheck {
+    word: String,
+    depth: i64,
+    declared_outputs: usize,
+    // Set once a call's effect can't be determined; the rest of the body
+    // is then left unchecked instead of guessing.
+    unknown: bool,
+}
+
+// A pending structured control-flow construct, tracked on the compile-time
+// control stack while its body is being compiled.
+enum ControlFrame {
+    // Address of the `JZ` to patch at the matching `ELSE` or `THEN`.
+    If(usize),
+    // Address of the `BR` to patch at the matching `THEN`.
+    Else(usize),
+    // Address to jump back to on `UNTIL`.
+    Begin(usize),
+    // Address of the loop body start, which `LOOP` jumps back to.
+    Do(usize),
 }
 
 struct Word {
     name: String,
     inline_iseq: Vec<CompiledInstruction>,
+    // Declared (inputs, outputs) arity from a `( a b -- c )` comment right
+    // after the word's name, if it had one. `None` means unknown, and the
+    // stack checker skips arity checking of calls to it.
+    effect: Option<(usize, usize)>,
 }
 
 #[derive(Copy, Clone)]
 enum CompileMode {
     TopLevel,
     Definition,
+    // Just past a definition's name, deciding whether a `( ... -- ... )`
+    // stack-effect comment follows before the body starts.
+    DefinitionEffectAwait,
+    // Inside a stack-effect comment, counting tokens on each side of `--`.
+    DefinitionEffect {
+        inputs: usize,
+        outputs: usize,
+        saw_dash: bool,
+    },
     DefinitionBody,
+    // Waiting for the name token of a `VARIABLE` declaration.
+    VariableName,
+    // Waiting for the name token of a `CONSTANT` declaration; carries the
+    // value popped at compile time.
+    ConstantName(i64),
 }
 
 struct VM {
-    data_stack: Vec<usize>,
+    data_stack: Vec<i64>,
     call_stack: Vec<usize>,
+    // `DO`/`LOOP`'s [limit, index] frames, with the index on top. Kept
+    // separate from `call_stack` so a word called from inside a loop body
+    // doesn't push its return address on top of the enclosing loop's index.
+    loop_stack: Vec<usize>,
+    data_memory: Vec<i64>,
     instruction_memory: Vec<CompiledInstruction>,
     instruction_pointer: usize,
     compile_mode: CompileMode,
+    control_stack: Vec<ControlFrame>,
     words: Vec<Word>,
     in_comment: bool,
+    // Literal strings compiled from `." ..."`, indexed by `PRINTSTR`.
+    strings: Vec<String>,
+    // Start address of each `:` definition's body, in the order they were
+    // compiled, for naming the enclosing word in runtime diagnostics.
+    definitions: Vec<(usize, String)>,
+    // Live only while compiling the body of a definition with a declared
+    // stack effect.
+    effect_check: Option<EffectCheck>,
 }
 
 #[derive(Debug, Clone)]
 enum VMErr {
     StackUnderflow,
-    InvalidToken(String),
+    InvalidToken { token: String, line: usize, col: usize },
+    MemoryOutOfBounds,
+    DivisionByZero,
+    // Raised by `NEGATE`, `ABS`, or `/` on input that overflows i64, e.g.
+    // negating or taking the absolute value of `i64::MIN`.
+    IntegerOverflow,
+    // A runtime error raised while executing `instruction_memory[addr]`,
+    // annotated with the enclosing `:` definition, if any.
+    Runtime {
+        inner: Box<VMErr>,
+        addr: usize,
+        word: Option<String>,
+    },
+    // Raised by the compile-time stack checker: either the simulated depth
+    // went negative (a guaranteed underflow) or the body's final depth
+    // didn't match the declared output count.
+    StackEffectMismatch { word: String, reason: String },
 }
 
 impl VM {
@@ -53,54 +176,151 @@ impl VM {
         VM {
             data_stack: vec![],
             call_stack: vec![],
+            loop_stack: vec![],
+            data_memory: vec![],
             instruction_memory: Vec::new(),
             instruction_pointer: 0,
             compile_mode: CompileMode::TopLevel,
+            control_stack: vec![],
             in_comment: false,
+            strings: vec![],
+            definitions: vec![],
+            effect_check: None,
             words: vec![
                 Word {
                     name: String::from("DUP"),
                     inline_iseq: vec![CompiledInstruction::DUP],
+                    effect: None,
                 },
                 Word {
                     name: String::from("DROP"),
                     inline_iseq: vec![CompiledInstruction::DROP],
+                    effect: None,
                 },
                 Word {
                     name: String::from("SWAP"),
                     inline_iseq: vec![CompiledInstruction::SWAP],
+                    effect: None,
                 },
                 Word {
                     name: String::from("+"),
                     inline_iseq: vec![CompiledInstruction::ADD],
+                    effect: None,
                 },
                 Word {
                     name: String::from("-"),
                     inline_iseq: vec![CompiledInstruction::SUB],
+                    effect: None,
                 },
                 Word {
                     name: String::from("*"),
                     inline_iseq: vec![CompiledInstruction::MUL],
+                    effect: None,
                 },
                 Word {
                     name: String::from("/"),
                     inline_iseq: vec![CompiledInstruction::DIV],
+                    effect: None,
                 },
                 Word {
                     name: String::from("CALL"),
                     inline_iseq: vec![CompiledInstruction::CALL],
+                    effect: None,
                 },
                 Word {
                     name: String::from("JUMP"),
                     inline_iseq: vec![CompiledInstruction::JUMP],
+                    effect: None,
                 },
                 Word {
                     name: String::from(";"),
                     inline_iseq: vec![CompiledInstruction::RET],
+                    effect: None,
                 },
                 Word {
                     name: String::from("."),
                     inline_iseq: vec![CompiledInstruction::PRINT],
+                    effect: None,
+                },
+                Word {
+                    name: String::from("@"),
+                    inline_iseq: vec![CompiledInstruction::FETCH],
+                    effect: None,
+                },
+                Word {
+                    name: String::from("!"),
+                    inline_iseq: vec![CompiledInstruction::STORE],
+                    effect: None,
+                },
+                Word {
+                    name: String::from("HERE"),
+                    inline_iseq: vec![CompiledInstruction::HERE],
+                    effect: None,
+                },
+                Word {
+                    name: String::from("ALLOT"),
+                    inline_iseq: vec![CompiledInstruction::ALLOT],
+                    effect: None,
+                },
+                Word {
+                    name: String::from("="),
+                    inline_iseq: vec![CompiledInstruction::EQ],
+                    effect: None,
+                },
+                Word {
+                    name: String::from("<"),
+                    inline_iseq: vec![CompiledInstruction::LT],
+                    effect: None,
+                },
+                Word {
+                    name: String::from(">"),
+                    inline_iseq: vec![CompiledInstruction::GT],
+                    effect: None,
+                },
+                Word {
+                    name: String::from("<="),
+                    inline_iseq: vec![CompiledInstruction::LE],
+                    effect: None,
+                },
+                Word {
+                    name: String::from(">="),
+                    inline_iseq: vec![CompiledInstruction::GE],
+                    effect: None,
+                },
+                Word {
+                    name: String::from("DIVMOD"),
+                    inline_iseq: vec![CompiledInstruction::DIVMOD],
+                    effect: None,
+                },
+                Word {
+                    name: String::from("MOD"),
+                    inline_iseq: vec![CompiledInstruction::MOD],
+                    effect: None,
+                },
+                Word {
+                    name: String::from("NEGATE"),
+                    inline_iseq: vec![CompiledInstruction::NEGATE],
+                    effect: None,
+                },
+                Word {
+                    name: String::from("ABS"),
+                    inline_iseq: vec![CompiledInstruction::ABS],
+                    effect: None,
+                },
+                Word {
+                    name: String::from("EMIT"),
+                    inline_iseq: vec![CompiledInstruction::EMIT],
+                    effect: None,
+                },
+                Word {
+                    name: String::from("TYPE"),
+                    inline_iseq: vec![CompiledInstruction::TYPE],
+                    effect: None,
+                },
+                Word {
+                    name: String::from("I"),
+                    inline_iseq: vec![CompiledInstruction::I],
+                    effect: None,
                 },
             ],
         }
@@ -143,17 +363,20 @@ impl VM {
             CompiledInstruction::DIV => {
                 let b = self.pop_data_stack()?;
                 let a = self.pop_data_stack()?;
-                self.data_stack.push(a / b);
+                if b == 0 {
+                    return Err(VMErr::DivisionByZero);
+                }
+                self.data_stack.push(a.checked_div(b).ok_or(VMErr::IntegerOverflow)?);
             }
             CompiledInstruction::IMM(n) => {
                 self.data_stack.push(n);
             }
             CompiledInstruction::CALL => {
                 self.call_stack.push(self.instruction_pointer);
-                self.instruction_pointer = self.pop_data_stack()?;
+                self.instruction_pointer = self.pop_addr()?;
             }
             CompiledInstruction::JUMP => {
-                self.instruction_pointer = self.pop_data_stack()?;
+                self.instruction_pointer = self.pop_addr()?;
             }
             CompiledInstruction::RET => {
                 self.instruction_pointer = self.pop_call_stack()?;
@@ -162,12 +385,191 @@ impl VM {
                 let tos = self.pop_data_stack()?;
                 print!(" {}", tos);
             }
+            CompiledInstruction::FETCH => {
+                let addr = self.pop_addr()?;
+                let value = self.mem_get(addr)?;
+                self.data_stack.push(value);
+            }
+            CompiledInstruction::STORE => {
+                let addr = self.pop_addr()?;
+                let value = self.pop_data_stack()?;
+                self.mem_set(addr, value)?;
+            }
+            CompiledInstruction::HERE => {
+                self.data_stack.push(self.data_memory.len() as i64);
+            }
+            CompiledInstruction::ALLOT => {
+                let n = self.pop_data_stack()?;
+                let new_len = usize::try_from(n)
+                    .ok()
+                    .and_then(|n| self.data_memory.len().checked_add(n));
+                match new_len {
+                    Some(len) => self.data_memory.resize(len, 0),
+                    None => return Err(VMErr::MemoryOutOfBounds),
+                }
+            }
+            CompiledInstruction::JZ(target) => {
+                let tos = self.pop_data_stack()?;
+                if tos == 0 {
+                    self.instruction_pointer = target;
+                }
+            }
+            CompiledInstruction::BR(target) => {
+                self.instruction_pointer = target;
+            }
+            CompiledInstruction::DO => {
+                // stack effect: ( limit start -- ), held on the loop stack
+                // as [limit, index] with the index on top.
+                let start = self.pop_data_stack()?;
+                let limit = self.pop_data_stack()?;
+                self.loop_stack.push(limit as usize);
+                self.loop_stack.push(start as usize);
+            }
+            CompiledInstruction::LOOP(target) => {
+                let index = self.pop_loop_stack()?;
+                let limit = self.pop_loop_stack()?;
+                let next = index + 1;
+                if next < limit {
+                    self.loop_stack.push(limit);
+                    self.loop_stack.push(next);
+                    self.instruction_pointer = target;
+                }
+            }
+            CompiledInstruction::I => {
+                let index = *self.loop_stack.last().ok_or(VMErr::StackUnderflow)?;
+                self.data_stack.push(index as i64);
+            }
+            CompiledInstruction::EQ => {
+                let b = self.pop_data_stack()?;
+                let a = self.pop_data_stack()?;
+                self.data_stack.push(if a == b { -1 } else { 0 });
+            }
+            CompiledInstruction::LT => {
+                let b = self.pop_data_stack()?;
+                let a = self.pop_data_stack()?;
+                self.data_stack.push(if a < b { -1 } else { 0 });
+            }
+            CompiledInstruction::GT => {
+                let b = self.pop_data_stack()?;
+                let a = self.pop_data_stack()?;
+                self.data_stack.push(if a > b { -1 } else { 0 });
+            }
+            CompiledInstruction::LE => {
+                let b = self.pop_data_stack()?;
+                let a = self.pop_data_stack()?;
+                self.data_stack.push(if a <= b { -1 } else { 0 });
+            }
+            CompiledInstruction::GE => {
+                let b = self.pop_data_stack()?;
+                let a = self.pop_data_stack()?;
+                self.data_stack.push(if a >= b { -1 } else { 0 });
+            }
+            CompiledInstruction::DIVMOD => {
+                let b = self.pop_data_stack()?;
+                let a = self.pop_data_stack()?;
+                if b == 0 {
+                    return Err(VMErr::DivisionByZero);
+                }
+                self.data_stack.push(a % b);
+                self.data_stack.push(a / b);
+            }
+            CompiledInstruction::MOD => {
+                let b = self.pop_data_stack()?;
+                let a = self.pop_data_stack()?;
+                if b == 0 {
+                    return Err(VMErr::DivisionByZero);
+                }
+                self.data_stack.push(a % b);
+            }
+            CompiledInstruction::NEGATE => {
+                let a = self.pop_data_stack()?;
+                self.data_stack.push(a.checked_neg().ok_or(VMErr::IntegerOverflow)?);
+            }
+            CompiledInstruction::ABS => {
+                let a = self.pop_data_stack()?;
+                self.data_stack.push(a.checked_abs().ok_or(VMErr::IntegerOverflow)?);
+            }
+            CompiledInstruction::EMIT => {
+                let tos = self.pop_data_stack()?;
+                print!("{}", tos as u8 as char);
+            }
+            CompiledInstruction::TYPE => {
+                let len = self.pop_addr()?;
+                let addr = self.pop_addr()?;
+                for i in 0..len {
+                    let byte = self.mem_get(addr + i)?;
+                    print!("{}", byte as u8 as char);
+                }
+            }
+            CompiledInstruction::PRINTSTR(idx) => {
+                print!("{}", self.strings[idx]);
+            }
         }
 
         Ok(())
     }
 
-    fn pop_data_stack(&mut self) -> Result<usize, VMErr> {
+    fn mem_get(&self, addr: usize) -> Result<i64, VMErr> {
+        match self.data_memory.get(addr) {
+            Some(&n) => Ok(n),
+            None => Err(VMErr::MemoryOutOfBounds),
+        }
+    }
+
+    fn mem_set(&mut self, addr: usize, value: i64) -> Result<(), VMErr> {
+        match self.data_memory.get_mut(addr) {
+            Some(slot) => {
+                *slot = value;
+                Ok(())
+            }
+            None => Err(VMErr::MemoryOutOfBounds),
+        }
+    }
+
+    // Pops a cell and interprets it as a memory/instruction address. A
+    // negative cell wraps to an out-of-range index, which `mem_get`'s
+    // bounds check rejects for memory accesses; `CALL`/`JUMP` don't check
+    // the instruction address.
+    fn pop_addr(&mut self) -> Result<usize, VMErr> {
+        let n = self.pop_data_stack()?;
+        Ok(n as usize)
+    }
+
+    // The absolute index in `instruction_memory` that the next instruction
+    // pushed via `push_ins` will land at. While compiling a definition body,
+    // instructions go straight into `instruction_memory`; at top level they
+    // are staged in `ins_seq` and only appended when `run` executes the line,
+    // so the address is offset by the current end of `instruction_memory`.
+    fn next_addr(&self, ins_seq: &[CompiledInstruction]) -> usize {
+        match self.compile_mode {
+            CompileMode::DefinitionBody => self.instruction_memory.len(),
+            _ => self.instruction_memory.len() + ins_seq.len(),
+        }
+    }
+
+    fn push_ins(&mut self, ins_seq: &mut Vec<CompiledInstruction>, ins: CompiledInstruction) {
+        match self.compile_mode {
+            CompileMode::DefinitionBody => self.instruction_memory.push(ins),
+            _ => ins_seq.push(ins),
+        }
+    }
+
+    // Backpatch the `JZ`/`BR` at absolute address `addr` to jump to `target`.
+    fn patch_jump(&mut self, ins_seq: &mut [CompiledInstruction], addr: usize, target: usize) {
+        let base = self.instruction_memory.len();
+        let slot = if addr < base {
+            &mut self.instruction_memory[addr]
+        } else {
+            &mut ins_seq[addr - base]
+        };
+        match slot {
+            CompiledInstruction::JZ(t) => *t = target,
+            CompiledInstruction::BR(t) => *t = target,
+            _ => unreachable!("patch_jump target is not a branch instruction"),
+        }
+    }
+
+    fn pop_data_stack(&mut self) -> Result<i64, VMErr> {
         match self.data_stack.pop() {
             Some(n) => Ok(n),
             None => Err(VMErr::StackUnderflow),
@@ -181,6 +583,13 @@ impl VM {
         }
     }
 
+    fn pop_loop_stack(&mut self) -> Result<usize, VMErr> {
+        match self.loop_stack.pop() {
+            Some(n) => Ok(n),
+            None => Err(VMErr::StackUnderflow),
+        }
+    }
+
     // Places `ins_seq` somewhere in the instruction_memory and execute it.
     pub fn run(&mut self, ins_seq: &[CompiledInstruction]) -> Result<(), VMErr> {
         self.instruction_pointer = self.instruction_memory.len();
@@ -196,6 +605,7 @@ impl VM {
                 return Ok(());
             }
 
+            let executing_addr = self.instruction_pointer;
             let ins = *self.instruction_memory
                 .get(self.instruction_pointer)
                 .unwrap(); // XXX
@@ -204,34 +614,60 @@ impl VM {
             if let Err(err) = self.exec_ins(ins) {
                 // restore original instruction memory
                 self.instruction_memory.truncate(old_imem_len);
-                return Err(err);
+                return Err(VMErr::Runtime {
+                    inner: Box::new(err),
+                    addr: executing_addr,
+                    // addresses from `old_imem_len` on are this top-level
+                    // line, not part of any definition's body
+                    word: if executing_addr < old_imem_len {
+                        self.word_at(executing_addr).map(|s| s.to_string())
+                    } else {
+                        None
+                    },
+                });
             }
         }
     }
 
+    // The name of the `:` definition whose body contains `addr`, if any.
+    fn word_at(&self, addr: usize) -> Option<&str> {
+        self.definitions
+            .iter()
+            .rev()
+            .find(|(start, _)| *start <= addr)
+            .map(|(_, name)| name.as_str())
+    }
+
     pub fn in_compile_mode(&self) -> bool {
         match self.compile_mode {
             CompileMode::TopLevel => false,
             CompileMode::Definition => true,
+            CompileMode::DefinitionEffectAwait => true,
+            CompileMode::DefinitionEffect { .. } => true,
             CompileMode::DefinitionBody => true,
+            CompileMode::VariableName => true,
+            CompileMode::ConstantName(_) => true,
         }
     }
 
-    // Compiles `line` into a sequence of instructions which is appended to `ins_seq`.
-    // As a side-effect, when a ":" definition is occured, this will add a
-    // word to the dictionary.
+    // Compiles `line` (numbered `line_no`, for diagnostics) into a sequence
+    // of instructions which is appended to `ins_seq`. As a side-effect, when
+    // a ":" definition is occured, this will add a word to the dictionary.
     pub fn compile_line(
         &mut self,
         line: &str,
+        line_no: usize,
         ins_seq: &mut Vec<CompiledInstruction>,
     ) -> Result<(), VMErr> {
         let mut remainder: &str = line;
 
         loop {
+            // 1-indexed column of whatever `remainder` currently starts with.
+            let col = line.len() - remainder.len() + 1;
             match remainder.find(char::is_whitespace) {
                 None => {
                     if remainder.len() > 0 {
-                        let _ = self.compile_token(remainder, ins_seq)?;
+                        let _ = self.compile_token(remainder, line_no, col, ins_seq)?;
                     }
                     return Ok(());
                 }
@@ -239,8 +675,29 @@ impl VM {
                     if pos > 0 {
                         // if pos == 0, then we found a whitespace at the beginning
                         let (token, rest) = remainder.split_at(pos);
-                        let _ = self.compile_token(token, ins_seq)?;
-                        remainder = rest;
+                        if token == ".\"" || token == "s\"" {
+                            // `token` is a string-literal prefix: consume the
+                            // single delimiting space and everything up to
+                            // the closing quote as one unsplit literal.
+                            let body = &rest[1..];
+                            match body.find('"') {
+                                Some(qpos) => {
+                                    let (text, after) = body.split_at(qpos);
+                                    self.compile_string_literal(token, text, ins_seq)?;
+                                    remainder = &after[1..];
+                                }
+                                None => {
+                                    return Err(VMErr::InvalidToken {
+                                        token: token.into(),
+                                        line: line_no,
+                                        col,
+                                    });
+                                }
+                            }
+                        } else {
+                            let _ = self.compile_token(token, line_no, col, ins_seq)?;
+                            remainder = rest;
+                        }
                     } else {
                         let (_token, rest) = remainder.split_at(1);
                         remainder = rest;
@@ -250,11 +707,99 @@ impl VM {
         }
     }
 
+    // Compiles the body of a `." text"` or `s" text"` literal, `prefix`
+    // being which of the two it was.
+    fn compile_string_literal(
+        &mut self,
+        prefix: &str,
+        text: &str,
+        ins_seq: &mut Vec<CompiledInstruction>,
+    ) -> Result<(), VMErr> {
+        // a literal right after a definition's name starts its body
+        if let CompileMode::DefinitionEffectAwait = self.compile_mode {
+            self.compile_mode = CompileMode::DefinitionBody;
+        }
+        match prefix {
+            ".\"" => {
+                self.apply_effect_delta(Some(0))?;
+                let idx = self.strings.len();
+                self.strings.push(text.to_string());
+                self.push_ins(ins_seq, CompiledInstruction::PRINTSTR(idx));
+            }
+            "s\"" => {
+                self.apply_effect_delta(Some(2))?; // pushes (addr, len)
+                let addr = self.data_memory.len();
+                for byte in text.bytes() {
+                    self.data_memory.push(byte as i64);
+                }
+                self.push_ins(ins_seq, CompiledInstruction::IMM(addr as i64));
+                self.push_ins(ins_seq, CompiledInstruction::IMM(text.len() as i64));
+            }
+            _ => unreachable!("compile_string_literal called with unknown prefix"),
+        }
+        Ok(())
+    }
+
     fn compile_token(
         &mut self,
         token: &str,
+        line_no: usize,
+        col: usize,
         ins_seq: &mut Vec<CompiledInstruction>,
     ) -> Result<(), VMErr> {
+        // an optional `( a b -- c )` stack-effect annotation right after a
+        // definition's name; falls through to ordinary compilation if the
+        // name isn't followed by one
+        if let CompileMode::DefinitionEffectAwait = self.compile_mode {
+            if token == "(" {
+                self.compile_mode = CompileMode::DefinitionEffect {
+                    inputs: 0,
+                    outputs: 0,
+                    saw_dash: false,
+                };
+                return Ok(());
+            }
+            self.compile_mode = CompileMode::DefinitionBody;
+        }
+        if let CompileMode::DefinitionEffect { inputs, outputs, saw_dash } = self.compile_mode {
+            match token {
+                ")" => {
+                    if let Some(word) = self.words.last_mut() {
+                        word.effect = Some((inputs, outputs));
+                        self.effect_check = Some(EffectCheck {
+                            word: word.name.clone(),
+                            depth: inputs as i64,
+                            declared_outputs: outputs,
+                            unknown: false,
+                        });
+                    }
+                    self.compile_mode = CompileMode::DefinitionBody;
+                }
+                "--" => {
+                    self.compile_mode = CompileMode::DefinitionEffect {
+                        inputs,
+                        outputs,
+                        saw_dash: true,
+                    };
+                }
+                _ if saw_dash => {
+                    self.compile_mode = CompileMode::DefinitionEffect {
+                        inputs,
+                        outputs: outputs + 1,
+                        saw_dash,
+                    };
+                }
+                _ => {
+                    self.compile_mode = CompileMode::DefinitionEffect {
+                        inputs: inputs + 1,
+                        outputs,
+                        saw_dash,
+                    };
+                }
+            }
+            return Ok(());
+        }
+
         // process comments
         if self.in_comment {
             if token == ")" {
@@ -268,37 +813,162 @@ impl VM {
             }
         }
 
+        // structured control flow, usable both at top level and inside a
+        // definition body
+        match token {
+            "IF" => {
+                self.apply_effect_delta(Some(-1))?; // pops the test value
+                let addr = self.next_addr(ins_seq);
+                self.push_ins(ins_seq, CompiledInstruction::JZ(0));
+                self.control_stack.push(ControlFrame::If(addr));
+                return Ok(());
+            }
+            "ELSE" => {
+                // the two branches' effects aren't merged, so the rest of
+                // the definition is left unchecked
+                self.apply_effect_delta(None)?;
+                return match self.control_stack.pop() {
+                    Some(ControlFrame::If(if_addr)) => {
+                        let br_addr = self.next_addr(ins_seq);
+                        self.push_ins(ins_seq, CompiledInstruction::BR(0));
+                        let target = self.next_addr(ins_seq);
+                        self.patch_jump(ins_seq, if_addr, target);
+                        self.control_stack.push(ControlFrame::Else(br_addr));
+                        Ok(())
+                    }
+                    _ => Err(VMErr::InvalidToken { token: token.into(), line: line_no, col }),
+                };
+            }
+            "THEN" => {
+                return match self.control_stack.pop() {
+                    Some(ControlFrame::If(addr)) | Some(ControlFrame::Else(addr)) => {
+                        let target = self.next_addr(ins_seq);
+                        self.patch_jump(ins_seq, addr, target);
+                        Ok(())
+                    }
+                    _ => Err(VMErr::InvalidToken { token: token.into(), line: line_no, col }),
+                };
+            }
+            "BEGIN" => {
+                let addr = self.next_addr(ins_seq);
+                self.control_stack.push(ControlFrame::Begin(addr));
+                return Ok(());
+            }
+            "UNTIL" => {
+                self.apply_effect_delta(Some(-1))?; // pops the test value
+                return match self.control_stack.pop() {
+                    Some(ControlFrame::Begin(begin_addr)) => {
+                        self.push_ins(ins_seq, CompiledInstruction::JZ(begin_addr));
+                        Ok(())
+                    }
+                    _ => Err(VMErr::InvalidToken { token: token.into(), line: line_no, col }),
+                };
+            }
+            "DO" => {
+                // net effect across iterations isn't statically decidable here
+                self.apply_effect_delta(None)?;
+                self.push_ins(ins_seq, CompiledInstruction::DO);
+                let addr = self.next_addr(ins_seq);
+                self.control_stack.push(ControlFrame::Do(addr));
+                return Ok(());
+            }
+            "LOOP" => {
+                self.apply_effect_delta(None)?;
+                return match self.control_stack.pop() {
+                    Some(ControlFrame::Do(body_addr)) => {
+                        self.push_ins(ins_seq, CompiledInstruction::LOOP(body_addr));
+                        Ok(())
+                    }
+                    _ => Err(VMErr::InvalidToken { token: token.into(), line: line_no, col }),
+                };
+            }
+            _ => {}
+        }
+
         let compile_mode = self.compile_mode;
         match compile_mode {
+            // handled above, before reaching here
+            CompileMode::DefinitionEffectAwait | CompileMode::DefinitionEffect { .. } => {
+                unreachable!("stack-effect parsing always returns early")
+            }
             CompileMode::TopLevel => {
                 match token {
                     ":" => {
                         // starts a definition
                         self.compile_mode = CompileMode::Definition;
                     }
+                    "VARIABLE" => {
+                        // the name follows on the next token
+                        self.compile_mode = CompileMode::VariableName;
+                    }
+                    "CONSTANT" => {
+                        // the value was just compiled as the preceding IMM;
+                        // pop it at compile time, the name follows next
+                        match ins_seq.pop() {
+                            Some(CompiledInstruction::IMM(value)) => {
+                                self.compile_mode = CompileMode::ConstantName(value);
+                            }
+                            _ => return Err(VMErr::InvalidToken { token: token.into(), line: line_no, col }),
+                        }
+                    }
                     _ => {
-                        for ins in self.token_to_instruction_seq(token)? {
+                        for ins in self.token_to_instruction_seq(token, line_no, col)? {
                             ins_seq.push(ins);
                         }
                     }
                 }
             }
+            CompileMode::VariableName => {
+                // reserve one cell at HERE and bind `token` to its address
+                let addr = self.data_memory.len();
+                self.data_memory.push(0);
+                self.words.push(Word {
+                    name: token.into(),
+                    inline_iseq: vec![CompiledInstruction::IMM(addr as i64)],
+                    effect: None,
+                });
+                self.compile_mode = CompileMode::TopLevel;
+            }
+            CompileMode::ConstantName(value) => {
+                self.words.push(Word {
+                    name: token.into(),
+                    inline_iseq: vec![CompiledInstruction::IMM(value)],
+                    effect: None,
+                });
+                self.compile_mode = CompileMode::TopLevel;
+            }
             CompileMode::Definition => {
+                let body_addr = self.instruction_memory.len();
                 self.words.push(Word {
                     name: token.into(),
                     inline_iseq: vec![
-                        CompiledInstruction::IMM(self.instruction_memory.len()),
+                        CompiledInstruction::IMM(body_addr as i64),
                         CompiledInstruction::CALL,
                     ],
+                    effect: None,
                 });
-                self.compile_mode = CompileMode::DefinitionBody;
+                self.definitions.push((body_addr, token.into()));
+                self.compile_mode = CompileMode::DefinitionEffectAwait;
             }
             CompileMode::DefinitionBody => {
-                for ins in self.token_to_instruction_seq(token)? {
+                let effect = self.token_stack_effect(token);
+                self.apply_effect_delta(effect)?;
+                for ins in self.token_to_instruction_seq(token, line_no, col)? {
                     self.instruction_memory.push(ins);
                 }
                 if token == ";" {
                     // ends a definition
+                    if let Some(check) = self.effect_check.take() {
+                        if !check.unknown && check.depth != check.declared_outputs as i64 {
+                            return Err(VMErr::StackEffectMismatch {
+                                word: check.word.clone(),
+                                reason: format!(
+                                    "declared {} output(s) but body leaves {} on the stack",
+                                    check.declared_outputs, check.depth
+                                ),
+                            });
+                        }
+                    }
                     self.compile_mode = CompileMode::TopLevel;
                 }
             }
@@ -306,16 +976,21 @@ impl VM {
         Ok(())
     }
 
-    fn token_to_instruction_seq(&self, token: &str) -> Result<Vec<CompiledInstruction>, VMErr> {
+    fn token_to_instruction_seq(
+        &self,
+        token: &str,
+        line_no: usize,
+        col: usize,
+    ) -> Result<Vec<CompiledInstruction>, VMErr> {
         match self.lookup_word(token) {
             None => {
                 // it's not a word. it might be a number, or an invalid token
-                match usize::from_str(token) {
+                match i64::from_str(token) {
                     Ok(num) => {
                         return Ok(vec![CompiledInstruction::IMM(num)]);
                     }
                     Err(_) => {
-                        return Err(VMErr::InvalidToken(token.into()));
+                        return Err(VMErr::InvalidToken { token: token.into(), line: line_no, col });
                     }
                 }
             }
@@ -328,6 +1003,407 @@ impl VM {
     fn lookup_word(&self, token: &str) -> Option<&Word> {
         self.words.iter().find(|w| w.name == token)
     }
+
+    // Net stack-depth change from compiling `token`, for the stack checker.
+    // `None` means unknown: a number literal always pushes one cell; a
+    // builtin's effect comes from `instruction_effect`; a call to another
+    // `:` definition uses its declared effect, if it has one.
+    fn token_stack_effect(&self, token: &str) -> Option<i64> {
+        if i64::from_str(token).is_ok() {
+            return Some(1);
+        }
+        match self.lookup_word(token)?.inline_iseq.as_slice() {
+            [CompiledInstruction::IMM(_), CompiledInstruction::CALL] => {
+                let (inputs, outputs) = self.lookup_word(token)?.effect?;
+                Some(outputs as i64 - inputs as i64)
+            }
+            [single] => instruction_effect(single),
+            _ => None,
+        }
+    }
+
+    // Applies `delta` to the in-progress stack check, if one is active;
+    // `None` marks the rest of the definition unknown instead (used for
+    // constructs like `DO`/`LOOP` whose net effect across iterations isn't
+    // statically decidable here). A no-op outside a checked definition.
+    fn apply_effect_delta(&mut self, delta: Option<i64>) -> Result<(), VMErr> {
+        if let Some(check) = &mut self.effect_check {
+            if check.unknown {
+                return Ok(());
+            }
+            match delta {
+                Some(d) => check.depth += d,
+                None => {
+                    check.unknown = true;
+                    return Ok(());
+                }
+            }
+            if check.depth < 0 {
+                return Err(VMErr::StackEffectMismatch {
+                    word: check.word.clone(),
+                    reason: "guaranteed stack underflow: simulated depth went negative".into(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // Lowers the compiled program to standalone x86_64 NASM assembly (Linux
+    // syscall ABI). `entry` names the word to call from `_start`.
+    //
+    // The data stack lives in a dedicated buffer addressed by `r15`, and
+    // `DO`/`LOOP`'s [limit, index] frames live in another dedicated buffer
+    // addressed by `r14` — both kept separate from the machine stack,
+    // mirroring the VM's own three-way split between `data_stack`,
+    // `loop_stack`, and `call_stack`. `CALL`/`RET` are lowered onto the
+    // machine stack via `call`/`ret`; keeping loop frames off of it means a
+    // `call` from inside a loop body can't land on top of the loop's index.
+    // Every instruction gets an `addr_N:` label so `JZ`/`BR` (whose targets
+    // are compile-time constants) can jump to them directly, and
+    // `CALL`/`JUMP` (whose targets are only known once the preceding `IMM`
+    // runs) can reach them indirectly through `jump_table`.
+    pub fn compile_to_nasm(&self, entry: &str, out: &mut impl Write) -> io::Result<()> {
+        let entry_addr = match self.lookup_word(entry).map(|w| w.inline_iseq.as_slice()) {
+            Some([CompiledInstruction::IMM(addr), CompiledInstruction::CALL]) => *addr as usize,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no such definition: {}", entry),
+                ));
+            }
+        };
+
+        writeln!(out, "; Generated by toyforth's NASM backend. Assemble with:")?;
+        writeln!(out, ";   nasm -f elf64 out.asm -o out.o && ld out.o -o out")?;
+        writeln!(out, "global _start")?;
+        writeln!(out)?;
+        writeln!(out, "section .bss")?;
+        writeln!(out, "align 8")?;
+        writeln!(out, "data_stack: resq 65536")?;
+        writeln!(out, "loop_stack: resq 65536")?;
+        writeln!(out, "heap: resq 65536")?;
+        writeln!(out, "itoa_buf: resb 32")?;
+        writeln!(out)?;
+        writeln!(out, "section .data")?;
+        writeln!(out, "align 8")?;
+        writeln!(out, "heap_len: dq 0")?;
+        writeln!(out)?;
+        writeln!(out, "section .rodata")?;
+        writeln!(out, "align 8")?;
+        writeln!(out, "jump_table:")?;
+        for i in 0..self.instruction_memory.len() {
+            writeln!(out, "    dq addr_{}", i)?;
+        }
+        for (i, s) in self.strings.iter().enumerate() {
+            let bytes: Vec<String> = s.bytes().map(|b| b.to_string()).collect();
+            if bytes.is_empty() {
+                writeln!(out, "str_{}: db 0", i)?;
+            } else {
+                writeln!(out, "str_{}: db {}", i, bytes.join(","))?;
+            }
+            writeln!(out, "str_{}_len: equ $-str_{}", i, i)?;
+        }
+        writeln!(out)?;
+        writeln!(out, "section .text")?;
+        writeln!(out, "_start:")?;
+        writeln!(out, "    lea r15, [rel data_stack]")?;
+        writeln!(out, "    lea r14, [rel loop_stack]")?;
+        writeln!(out, "    call addr_{}", entry_addr)?;
+        writeln!(out, "    mov rax, 60")?;
+        writeln!(out, "    xor rdi, rdi")?;
+        writeln!(out, "    syscall")?;
+        writeln!(out)?;
+        self.emit_print_i64(out)?;
+        writeln!(out)?;
+        self.emit_type_out(out)?;
+        writeln!(out)?;
+
+        for (i, ins) in self.instruction_memory.iter().enumerate() {
+            writeln!(out, "addr_{}:", i)?;
+            self.emit_instruction(out, *ins)?;
+        }
+        Ok(())
+    }
+
+    fn emit_instruction(&self, out: &mut impl Write, ins: CompiledInstruction) -> io::Result<()> {
+        match ins {
+            CompiledInstruction::NOP => {
+                writeln!(out, "    nop")?;
+            }
+            CompiledInstruction::IMM(n) => {
+                writeln!(out, "    mov qword [r15], {}", n)?;
+                writeln!(out, "    add r15, 8")?;
+            }
+            CompiledInstruction::DUP => {
+                writeln!(out, "    mov rax, [r15-8]")?;
+                writeln!(out, "    mov [r15], rax")?;
+                writeln!(out, "    add r15, 8")?;
+            }
+            CompiledInstruction::DROP => {
+                writeln!(out, "    sub r15, 8")?;
+            }
+            CompiledInstruction::SWAP => {
+                writeln!(out, "    mov rax, [r15-8]")?;
+                writeln!(out, "    mov rbx, [r15-16]")?;
+                writeln!(out, "    mov [r15-8], rbx")?;
+                writeln!(out, "    mov [r15-16], rax")?;
+            }
+            CompiledInstruction::ADD => self.emit_binop(out, "add rax, rbx")?,
+            CompiledInstruction::SUB => self.emit_binop(out, "sub rax, rbx")?,
+            CompiledInstruction::MUL => self.emit_binop(out, "imul rax, rbx")?,
+            CompiledInstruction::DIV => {
+                writeln!(out, "    sub r15, 16")?;
+                writeln!(out, "    mov rax, [r15]")?;
+                writeln!(out, "    mov rbx, [r15+8]")?;
+                writeln!(out, "    cqo")?;
+                writeln!(out, "    idiv rbx")?;
+                writeln!(out, "    mov [r15], rax")?;
+                writeln!(out, "    add r15, 8")?;
+            }
+            CompiledInstruction::DIVMOD => {
+                writeln!(out, "    sub r15, 16")?;
+                writeln!(out, "    mov rax, [r15]")?;
+                writeln!(out, "    mov rbx, [r15+8]")?;
+                writeln!(out, "    cqo")?;
+                writeln!(out, "    idiv rbx")?;
+                writeln!(out, "    mov [r15], rdx")?;
+                writeln!(out, "    mov [r15+8], rax")?;
+                writeln!(out, "    add r15, 16")?;
+            }
+            CompiledInstruction::MOD => {
+                writeln!(out, "    sub r15, 16")?;
+                writeln!(out, "    mov rax, [r15]")?;
+                writeln!(out, "    mov rbx, [r15+8]")?;
+                writeln!(out, "    cqo")?;
+                writeln!(out, "    idiv rbx")?;
+                writeln!(out, "    mov [r15], rdx")?;
+                writeln!(out, "    add r15, 8")?;
+            }
+            CompiledInstruction::NEGATE => {
+                writeln!(out, "    mov rax, [r15-8]")?;
+                writeln!(out, "    neg rax")?;
+                writeln!(out, "    mov [r15-8], rax")?;
+            }
+            CompiledInstruction::ABS => {
+                writeln!(out, "    mov rax, [r15-8]")?;
+                writeln!(out, "    cqo")?;
+                writeln!(out, "    xor rax, rdx")?;
+                writeln!(out, "    sub rax, rdx")?;
+                writeln!(out, "    mov [r15-8], rax")?;
+            }
+            CompiledInstruction::EQ => self.emit_compare(out, "sete")?,
+            CompiledInstruction::LT => self.emit_compare(out, "setl")?,
+            CompiledInstruction::GT => self.emit_compare(out, "setg")?,
+            CompiledInstruction::LE => self.emit_compare(out, "setle")?,
+            CompiledInstruction::GE => self.emit_compare(out, "setge")?,
+            CompiledInstruction::CALL => {
+                writeln!(out, "    sub r15, 8")?;
+                writeln!(out, "    mov rax, [r15]")?;
+                writeln!(out, "    call [rel jump_table + rax*8]")?;
+            }
+            CompiledInstruction::JUMP => {
+                writeln!(out, "    sub r15, 8")?;
+                writeln!(out, "    mov rax, [r15]")?;
+                writeln!(out, "    jmp [rel jump_table + rax*8]")?;
+            }
+            CompiledInstruction::RET => {
+                writeln!(out, "    ret")?;
+            }
+            CompiledInstruction::JZ(target) => {
+                writeln!(out, "    sub r15, 8")?;
+                writeln!(out, "    cmp qword [r15], 0")?;
+                writeln!(out, "    je addr_{}", target)?;
+            }
+            CompiledInstruction::BR(target) => {
+                writeln!(out, "    jmp addr_{}", target)?;
+            }
+            CompiledInstruction::DO => {
+                // ( limit start -- ), pushed onto the loop buffer as
+                // [limit, index] with the index on top, like `loop_stack`.
+                writeln!(out, "    sub r15, 16")?;
+                writeln!(out, "    mov rax, [r15]")?;
+                writeln!(out, "    mov [r14], rax")?;
+                writeln!(out, "    mov rax, [r15+8]")?;
+                writeln!(out, "    mov [r14+8], rax")?;
+                writeln!(out, "    add r14, 16")?;
+            }
+            CompiledInstruction::LOOP(target) => {
+                writeln!(out, "    sub r14, 16")?;
+                writeln!(out, "    mov rax, [r14+8]")?;
+                writeln!(out, "    mov rbx, [r14]")?;
+                writeln!(out, "    inc rax")?;
+                writeln!(out, "    cmp rax, rbx")?;
+                writeln!(out, "    jge .loop_done_{}", target)?;
+                writeln!(out, "    mov [r14], rbx")?;
+                writeln!(out, "    mov [r14+8], rax")?;
+                writeln!(out, "    add r14, 16")?;
+                writeln!(out, "    jmp addr_{}", target)?;
+                writeln!(out, ".loop_done_{}:", target)?;
+            }
+            CompiledInstruction::I => {
+                // top of the loop buffer is the loop index, mirroring how
+                // `I` peeks the top of `loop_stack` in the VM
+                writeln!(out, "    mov rax, [r14-8]")?;
+                writeln!(out, "    mov [r15], rax")?;
+                writeln!(out, "    add r15, 8")?;
+            }
+            CompiledInstruction::PRINT => {
+                writeln!(out, "    sub r15, 8")?;
+                writeln!(out, "    mov rax, [r15]")?;
+                writeln!(out, "    call print_i64")?;
+            }
+            CompiledInstruction::HERE => {
+                writeln!(out, "    mov rax, [rel heap_len]")?;
+                writeln!(out, "    mov [r15], rax")?;
+                writeln!(out, "    add r15, 8")?;
+            }
+            CompiledInstruction::ALLOT => {
+                writeln!(out, "    sub r15, 8")?;
+                writeln!(out, "    mov rax, [r15]")?;
+                writeln!(out, "    add [rel heap_len], rax")?;
+            }
+            CompiledInstruction::FETCH => {
+                writeln!(out, "    mov rax, [r15-8]")?;
+                writeln!(out, "    lea rbx, [rel heap]")?;
+                writeln!(out, "    mov rax, [rbx + rax*8]")?;
+                writeln!(out, "    mov [r15-8], rax")?;
+            }
+            CompiledInstruction::STORE => {
+                writeln!(out, "    sub r15, 16")?;
+                writeln!(out, "    mov rax, [r15+8]")?; // addr
+                writeln!(out, "    mov rbx, [r15]")?; // value
+                writeln!(out, "    lea rcx, [rel heap]")?;
+                writeln!(out, "    mov [rcx + rax*8], rbx")?;
+            }
+            CompiledInstruction::EMIT => {
+                writeln!(out, "    sub r15, 8")?;
+                writeln!(out, "    mov al, [r15]")?;
+                writeln!(out, "    mov [rel itoa_buf], al")?;
+                writeln!(out, "    mov rax, 1")?;
+                writeln!(out, "    mov rdi, 1")?;
+                writeln!(out, "    lea rsi, [rel itoa_buf]")?;
+                writeln!(out, "    mov rdx, 1")?;
+                writeln!(out, "    syscall")?;
+            }
+            CompiledInstruction::TYPE => {
+                writeln!(out, "    sub r15, 16")?;
+                writeln!(out, "    mov rax, [r15]")?; // addr
+                writeln!(out, "    mov rcx, [r15+8]")?; // len
+                writeln!(out, "    call type_out")?;
+            }
+            CompiledInstruction::PRINTSTR(idx) => {
+                writeln!(out, "    mov rax, 1")?;
+                writeln!(out, "    mov rdi, 1")?;
+                writeln!(out, "    lea rsi, [rel str_{}]", idx)?;
+                writeln!(out, "    mov rdx, str_{}_len", idx)?;
+                writeln!(out, "    syscall")?;
+            }
+        }
+        Ok(())
+    }
+
+    // Pops `b` then `a` (`a` was pushed first), runs `op` with them in
+    // `rax`/`rbx`, and pushes the result in `rax`.
+    fn emit_binop(&self, out: &mut impl Write, op: &str) -> io::Result<()> {
+        writeln!(out, "    sub r15, 16")?;
+        writeln!(out, "    mov rax, [r15]")?;
+        writeln!(out, "    mov rbx, [r15+8]")?;
+        writeln!(out, "    {}", op)?;
+        writeln!(out, "    mov [r15], rax")?;
+        writeln!(out, "    add r15, 8")?;
+        Ok(())
+    }
+
+    // Pops `b` then `a`, compares them with `setcc`, and pushes -1/0 per
+    // the Forth truth convention.
+    fn emit_compare(&self, out: &mut impl Write, setcc: &str) -> io::Result<()> {
+        writeln!(out, "    sub r15, 16")?;
+        writeln!(out, "    mov rax, [r15]")?;
+        writeln!(out, "    mov rbx, [r15+8]")?;
+        writeln!(out, "    cmp rax, rbx")?;
+        writeln!(out, "    {} al", setcc)?;
+        writeln!(out, "    movzx rax, al")?;
+        writeln!(out, "    neg rax")?;
+        writeln!(out, "    mov [r15], rax")?;
+        writeln!(out, "    add r15, 8")?;
+        Ok(())
+    }
+
+    // Writes `rcx` cells from `heap` starting at cell index `rax`, one byte
+    // at a time (each cell holds a byte value, like `STORE`/`FETCH`).
+    fn emit_type_out(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "type_out:")?;
+        writeln!(out, "    lea rbx, [rel heap]")?;
+        writeln!(out, ".loop:")?;
+        writeln!(out, "    test rcx, rcx")?;
+        writeln!(out, "    jz .done")?;
+        writeln!(out, "    mov dl, [rbx + rax*8]")?;
+        writeln!(out, "    mov [rel itoa_buf], dl")?;
+        writeln!(out, "    push rax")?;
+        writeln!(out, "    push rbx")?;
+        writeln!(out, "    push rcx")?;
+        writeln!(out, "    mov rax, 1")?;
+        writeln!(out, "    mov rdi, 1")?;
+        writeln!(out, "    lea rsi, [rel itoa_buf]")?;
+        writeln!(out, "    mov rdx, 1")?;
+        writeln!(out, "    syscall")?;
+        writeln!(out, "    pop rcx")?;
+        writeln!(out, "    pop rbx")?;
+        writeln!(out, "    pop rax")?;
+        writeln!(out, "    inc rax")?;
+        writeln!(out, "    dec rcx")?;
+        writeln!(out, "    jmp .loop")?;
+        writeln!(out, ".done:")?;
+        writeln!(out, "    ret")?;
+        Ok(())
+    }
+
+    // Prints the i64 in `rax` the same way `PRINT` does: a leading space
+    // then the decimal digits, no trailing newline.
+    fn emit_print_i64(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "print_i64:")?;
+        writeln!(out, "    push rbx")?;
+        writeln!(out, "    push rcx")?;
+        writeln!(out, "    push rdx")?;
+        writeln!(out, "    push rsi")?;
+        writeln!(out, "    push rdi")?;
+        writeln!(out, "    mov rdi, rax")?; // keep the signed original around
+        writeln!(out, "    lea rsi, [rel itoa_buf+31]")?;
+        writeln!(out, "    mov rbx, 10")?;
+        writeln!(out, "    xor rcx, rcx")?; // digit count
+        writeln!(out, "    cmp rax, 0")?;
+        writeln!(out, "    jns .digit_loop")?;
+        writeln!(out, "    neg rax")?;
+        writeln!(out, ".digit_loop:")?;
+        writeln!(out, "    xor rdx, rdx")?;
+        writeln!(out, "    div rbx")?;
+        writeln!(out, "    add dl, '0'")?;
+        writeln!(out, "    dec rsi")?;
+        writeln!(out, "    mov [rsi], dl")?;
+        writeln!(out, "    inc rcx")?;
+        writeln!(out, "    test rax, rax")?;
+        writeln!(out, "    jnz .digit_loop")?;
+        writeln!(out, "    cmp rdi, 0")?;
+        writeln!(out, "    jns .has_sign")?;
+        writeln!(out, "    dec rsi")?;
+        writeln!(out, "    mov byte [rsi], '-'")?;
+        writeln!(out, "    inc rcx")?;
+        writeln!(out, ".has_sign:")?;
+        writeln!(out, "    dec rsi")?;
+        writeln!(out, "    mov byte [rsi], ' '")?;
+        writeln!(out, "    inc rcx")?;
+        writeln!(out, "    mov rax, 1")?; // write syscall
+        writeln!(out, "    mov rdi, 1")?; // fd 1 (stdout)
+        writeln!(out, "    mov rdx, rcx")?;
+        writeln!(out, "    syscall")?;
+        writeln!(out, "    pop rdi")?;
+        writeln!(out, "    pop rsi")?;
+        writeln!(out, "    pop rdx")?;
+        writeln!(out, "    pop rcx")?;
+        writeln!(out, "    pop rbx")?;
+        writeln!(out, "    ret")?;
+        Ok(())
+    }
 }
 
 fn read_line() -> String {
@@ -336,14 +1412,63 @@ fn read_line() -> String {
     return iterator.next().unwrap().unwrap();
 }
 
+// Prints `err` against the source `line` it came from: a caret underline
+// under the offending token for invalid tokens, or the enclosing word and
+// instruction address for runtime errors.
+fn print_error(err: &VMErr, line: &str) {
+    match err {
+        VMErr::InvalidToken { token, line: line_no, col } => {
+            println!("{}", line);
+            println!("{}^", " ".repeat(col - 1));
+            println!("Error: invalid token {:?} (line {}, col {})", token, line_no, col);
+        }
+        VMErr::Runtime { inner, addr, word } => {
+            match word {
+                Some(word) => {
+                    println!("Error: {:?} in {} (instruction {})", inner, word, addr);
+                }
+                None => {
+                    println!("Error: {:?} (instruction {})", inner, addr);
+                }
+            }
+        }
+        VMErr::StackEffectMismatch { word, reason } => {
+            println!("Error: bad stack effect in {}: {}", word, reason);
+        }
+        other => println!("Error: {:?}", other),
+    }
+}
+
 fn main() {
     println!("MiniForth started");
     let mut vm = VM::new();
     let mut ins_seq = Vec::new();
+    let mut line_no = 0;
     loop {
         let line = read_line();
+        line_no += 1;
+
+        // Meta-command, not part of the Forth language: `.nasm <entry>
+        // <outfile>` lowers the compiled program to standalone x86_64 NASM
+        // assembly (see `compile_to_nasm`), naming `entry` as the word to
+        // call from `_start`.
+        let mut parts = line.split_whitespace();
+        if parts.next() == Some(".nasm") {
+            match (parts.next(), parts.next()) {
+                (Some(entry), Some(path)) => match File::create(path) {
+                    Ok(mut file) => match vm.compile_to_nasm(entry, &mut file) {
+                        Ok(()) => println!(" wrote {}", path),
+                        Err(err) => println!("Error: {}", err),
+                    },
+                    Err(err) => println!("Error: {}", err),
+                },
+                _ => println!("Error: usage: .nasm <entry> <outfile>"),
+            }
+            continue;
+        }
+
         ins_seq.clear();
-        match vm.compile_line(&line, &mut ins_seq) {
+        match vm.compile_line(&line, line_no, &mut ins_seq) {
             Ok(()) => {
                 match vm.run(&ins_seq) {
                     Ok(()) => {
@@ -354,13 +1479,117 @@ fn main() {
                         }
                     }
                     Err(err) => {
-                        println!("Error: {:?}", err);
+                        print_error(&err, &line);
                     }
                 }
             }
             Err(err) => {
-                println!("Error: {:?}", err);
+                print_error(&err, &line);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Smoke test for `compile_to_nasm`: it's only reachable via the `.nasm`
+    // REPL command, so exercise it directly here.
+    #[test]
+    fn compile_to_nasm_emits_entry_and_jump_table() {
+        let mut vm = VM::new();
+        let mut ins_seq = Vec::new();
+        vm.compile_line(": SQUARE DUP * ;", 1, &mut ins_seq).unwrap();
+        vm.run(&ins_seq).unwrap();
+
+        let mut out = Vec::new();
+        vm.compile_to_nasm("SQUARE", &mut out).unwrap();
+        let asm = String::from_utf8(out).unwrap();
+
+        assert!(asm.contains("global _start"));
+        assert!(asm.contains("jump_table:"));
+        assert!(asm.contains("call addr_0"));
+    }
+
+    #[test]
+    fn compile_to_nasm_rejects_unknown_entry() {
+        let vm = VM::new();
+        let mut out = Vec::new();
+        assert!(vm.compile_to_nasm("NOSUCHWORD", &mut out).is_err());
+    }
+
+    #[test]
+    fn allot_rejects_negative_count() {
+        let mut vm = VM::new();
+        let mut ins_seq = Vec::new();
+        vm.compile_line("-1 ALLOT", 1, &mut ins_seq).unwrap();
+        match vm.run(&ins_seq) {
+            Err(VMErr::Runtime { inner, .. }) => assert!(matches!(*inner, VMErr::MemoryOutOfBounds)),
+            other => panic!("expected Runtime(MemoryOutOfBounds), got {:?}", other),
+        }
+    }
+
+    // `I` must read the innermost DO...LOOP's index even when the word
+    // reading it is called from inside the loop body, not inlined into it.
+    #[test]
+    fn i_survives_a_call_from_inside_a_loop_body() {
+        let mut vm = VM::new();
+        let mut ins_seq = Vec::new();
+        vm.compile_line(": PUSHI I ;", 1, &mut ins_seq).unwrap();
+        vm.run(&ins_seq).unwrap();
+        ins_seq.clear();
+        vm.compile_line(": T 3 0 DO PUSHI LOOP ;", 2, &mut ins_seq).unwrap();
+        vm.run(&ins_seq).unwrap();
+        ins_seq.clear();
+        vm.compile_line("T", 3, &mut ins_seq).unwrap();
+        vm.run(&ins_seq).unwrap();
+
+        assert_eq!(vm.data_stack, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn negate_rejects_i64_min_instead_of_panicking() {
+        let mut vm = VM::new();
+        let mut ins_seq = Vec::new();
+        vm.compile_line(&format!("{} NEGATE", i64::MIN), 1, &mut ins_seq).unwrap();
+        match vm.run(&ins_seq) {
+            Err(VMErr::Runtime { inner, .. }) => assert!(matches!(*inner, VMErr::IntegerOverflow)),
+            other => panic!("expected Runtime(IntegerOverflow), got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn string_literal_writes_bytes_to_data_memory() {
+        let mut vm = VM::new();
+        let mut ins_seq = Vec::new();
+        vm.compile_line(r#"s" hi""#, 1, &mut ins_seq).unwrap();
+        vm.run(&ins_seq).unwrap();
+
+        assert_eq!(vm.data_stack, vec![0, 2]);
+        assert_eq!(&vm.data_memory, &[b'h' as i64, b'i' as i64]);
+    }
+
+    #[test]
+    fn invalid_token_reports_its_line_and_column() {
+        let mut vm = VM::new();
+        let mut ins_seq = Vec::new();
+        match vm.compile_line("1 BADTOKEN", 5, &mut ins_seq) {
+            Err(VMErr::InvalidToken { token, line, col }) => {
+                assert_eq!(token, "BADTOKEN");
+                assert_eq!(line, 5);
+                assert_eq!(col, 3);
+            }
+            other => panic!("expected InvalidToken, got {:?}", other),
+        }
+    }
+
+    // A word's declared stack effect must account for `s"`'s (addr, len)
+    // push, not just the tokens compiled through `compile_token`.
+    #[test]
+    fn declared_effect_accepts_string_literal() {
+        let mut vm = VM::new();
+        let mut ins_seq = Vec::new();
+        assert!(vm.compile_line(r#": SAY ( -- ) s" hi" TYPE ;"#, 1, &mut ins_seq).is_ok());
+    }
+}